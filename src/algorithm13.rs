@@ -3,37 +3,81 @@ use std::hash::Hash;
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Ord, PartialOrd)]
 pub struct Register(u32);
 
+/// A storage location a parallel copy can read from or write to. `Stack` and
+/// `Imm` let the sequentializer drive real out-of-SSA lowering, where phi
+/// arguments mix registers, spilled values, and constants.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Ord, PartialOrd)]
-pub struct RegisterCopy {
-    pub source: Register,
-    pub destination: Register,
+pub enum Location {
+    Reg(Register),
+    Stack(u32),
+    Imm(u64),
 }
 
-fn sequentialize_register(parallel_copies: &[RegisterCopy], spare: Register) -> Vec<RegisterCopy> {
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Ord, PartialOrd)]
+pub struct Copy {
+    pub source: Location,
+    pub destination: Location,
+}
+
+/// A sequentialized step: either a plain move, or (in spare-free mode) an
+/// exchange used to break a cycle without a temporary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Copy { source: Location, destination: Location },
+    Swap { a: Location, b: Location },
+}
+
+/// How `sequentialize` should break the cycles left over once all acyclic
+/// moves have been materialized.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CycleBreak {
+    /// Route one copy of each cycle through `Location`, which must not
+    /// otherwise appear as a source or destination, and must be of the same
+    /// class (e.g. register vs. stack slot) as the cycle it breaks.
+    Spare(Location),
+    /// Rotate each cycle into place with a chain of exchanges, needing no
+    /// spare location but requiring an exchange instruction.
+    Swap,
+}
+
+fn same_class(a: Location, b: Location) -> bool {
+    std::mem::discriminant(&a) == std::mem::discriminant(&b)
+}
+
+/// Turns a set of parallel copies (as produced by SSA destruction) into an
+/// equivalent sequence of ordinary moves/swaps. Sources that are never
+/// themselves overwritten by another copy in the set - constants, or values
+/// nothing else depends on - are treated as leaves: they get materialized
+/// whenever something reads them and never take part in a cycle. A cycle can
+/// only consist of writable locations, since an immediate can never be a
+/// copy's destination.
+fn sequentialize(parallel_copies: &[Copy], cycle_break: CycleBreak) -> Vec<Op> {
     let mut sequentialized = Vec::new();
-    // `resource` in the original code, this point to the current register holding a particular initial value.
-    // If a given Register is no longer needed, the value might be inaccurate.
+    // `resource` in the original code, this point to the current location holding a particular initial value.
+    // If a given Location is no longer needed, the value might be inaccurate.
     let mut current_holder = std::collections::HashMap::new();
-    // Copies that are pending, indexed by destination register.
+    // Copies that are pending, indexed by destination location.
     // Use btree map to stay deterministic.
     let mut pending = std::collections::BTreeMap::new();
     // If a copy can be materialized (nothing depends on the destination), we move it from pending into available.
     let mut available = Vec::new();
 
     for copy in parallel_copies {
-        if copy.source == spare || copy.destination == spare {
-            panic!("Spare register cannot be a source or destination of a copy");
+        if let CycleBreak::Spare(spare) = cycle_break {
+            if copy.source == spare || copy.destination == spare {
+                panic!("Spare location cannot be a source or destination of a copy");
+            }
         }
         if let Some(_old_value) = pending.insert(copy.destination, copy) {
             panic!(
-                "Destination register {:?} has multiple copies.",
+                "Destination location {:?} has multiple copies.",
                 copy.destination
             );
         }
         current_holder.insert(copy.source, copy.source);
     }
     for copy in parallel_copies {
-        // If we didn't record it, this means nothing depends on that register.
+        // If we didn't record it, this means nothing depends on that location.
         if !current_holder.contains_key(&copy.destination) {
             pending.remove(&copy.destination);
             available.push(copy);
@@ -43,34 +87,70 @@ fn sequentialize_register(parallel_copies: &[RegisterCopy], spare: Register) ->
         while let Some(copy) = available.pop() {
             if let Some(source) = current_holder.get_mut(&copy.source) {
                 // Materialize the copy.
-                sequentialized.push(RegisterCopy {
-                    source: source.clone(),
+                sequentialized.push(Op::Copy {
+                    source: *source,
                     destination: copy.destination,
                 });
                 if let Some(available_copy) = pending.remove(source) {
                     available.push(available_copy);
                     // Point to the new destination.
                     *source = copy.destination;
-                } else if *source == spare {
-                    // Also point to new destination if we were copying from a spare, this lets us reuse spare for the next cycle.
-                    *source = copy.destination;
+                } else if let CycleBreak::Spare(spare) = cycle_break {
+                    if *source == spare {
+                        // Also point to new destination if we were copying from a spare, this lets us reuse spare for the next cycle.
+                        *source = copy.destination;
+                    }
                 }
             } else {
-                panic!("No holder for source register {:?}", copy.source);
+                panic!("No holder for source location {:?}", copy.source);
             }
         }
-        if let Some((destination,  copy)) = pending.iter().next() {
-            sequentialized.push(RegisterCopy {
-                source: copy.destination,
-                destination: spare,
-            });
-            current_holder.insert(copy.destination, spare);
-            available.push(copy);
-            let to_remove = *destination;
-            pending.remove(&to_remove);
-        } else {
+        let Some((&destination, &copy)) = pending.iter().next() else {
             // nothing pending.
             break;
+        };
+        match cycle_break {
+            CycleBreak::Spare(spare) => {
+                if !same_class(spare, copy.destination) {
+                    panic!(
+                        "Spare location {:?} does not match the class of cycle location {:?}",
+                        spare, copy.destination
+                    );
+                }
+                sequentialized.push(Op::Copy {
+                    source: copy.destination,
+                    destination: spare,
+                });
+                current_holder.insert(copy.destination, spare);
+                available.push(copy);
+                pending.remove(&destination);
+            }
+            CycleBreak::Swap => {
+                // `destination` is part of a disjoint cycle (every acyclic
+                // move has already been drained into `available`). Walk it
+                // by following `source` links from one destination to the
+                // next until we get back to where we started.
+                let mut cycle = vec![destination];
+                let mut node = pending[&destination].source;
+                while node != destination {
+                    cycle.push(node);
+                    node = pending[&node].source;
+                }
+                for node in &cycle {
+                    pending.remove(node);
+                }
+
+                // `cycle` was built walking destination-to-source, so it
+                // runs backwards relative to the value flow r1 -> r2 -> ...
+                // -> rk; flip it so cycle[i] -> cycle[i+1] matches a copy.
+                cycle.reverse();
+                for pair in cycle.windows(2).rev() {
+                    sequentialized.push(Op::Swap {
+                        a: pair[0],
+                        b: pair[1],
+                    });
+                }
+            }
         }
     }
     sequentialized
@@ -84,117 +164,96 @@ mod tests {
     use super::*;
     use assert_matches::assert_matches;
 
-    // Assumes that each register initially contains the value matching its id.
-    fn execute_sequential(copies: &[RegisterCopy]) -> HashMap<Register, u32> {
-        let mut register_values = HashMap::new();
-        for copy in copies {
-            let source_value = *register_values.get(&copy.source).unwrap_or(&copy.source.0);
-            register_values.insert(copy.destination, source_value);
+    fn reg(id: u32) -> Location {
+        Location::Reg(Register(id))
+    }
+
+    // Assumes that each location initially contains the value matching its register id or
+    // stack slot number; an immediate always holds its own value.
+    fn execute_sequential(ops: &[Op]) -> HashMap<Location, u64> {
+        let mut values = HashMap::new();
+        let initial = |loc: &Location| match loc {
+            Location::Reg(Register(id)) => *id as u64,
+            Location::Stack(slot) => *slot as u64,
+            Location::Imm(value) => *value,
+        };
+        for op in ops {
+            match *op {
+                Op::Copy { source, destination } => {
+                    let source_value = *values.get(&source).unwrap_or(&initial(&source));
+                    values.insert(destination, source_value);
+                }
+                Op::Swap { a, b } => {
+                    let a_value = *values.get(&a).unwrap_or(&initial(&a));
+                    let b_value = *values.get(&b).unwrap_or(&initial(&b));
+                    values.insert(a, b_value);
+                    values.insert(b, a_value);
+                }
+            }
         }
-        register_values
+        values
     }
 
-    fn execute_parallel(copies: &[RegisterCopy]) -> HashMap<Register, u32> {
-        let mut register_values = HashMap::new();
+    fn execute_parallel(copies: &[Copy]) -> HashMap<Location, u64> {
+        let mut values = HashMap::new();
         for copy in copies {
-            register_values.insert(copy.destination, copy.source.0);
+            let value = match copy.source {
+                Location::Reg(Register(id)) => id as u64,
+                Location::Stack(slot) => slot as u64,
+                Location::Imm(value) => value,
+            };
+            values.insert(copy.destination, value);
         }
-        register_values
+        values
     }
 
     #[test]
     fn test_execute_sequential() {
-        let copies = vec![
-            RegisterCopy {
-                source: Register(1),
-                destination: Register(2),
-            },
-            RegisterCopy {
-                source: Register(3),
-                destination: Register(2),
-            },
-            RegisterCopy {
-                source: Register(2),
-                destination: Register(4),
-            },
-            RegisterCopy {
-                source: Register(2),
-                destination: Register(1),
-            },
-            RegisterCopy {
-                source: Register(5),
-                destination: Register(3),
-            },
+        let ops = vec![
+            Op::Copy { source: reg(1), destination: reg(2) },
+            Op::Copy { source: reg(3), destination: reg(2) },
+            Op::Copy { source: reg(2), destination: reg(4) },
+            Op::Copy { source: reg(2), destination: reg(1) },
+            Op::Copy { source: reg(5), destination: reg(3) },
         ];
-        let result = execute_sequential(&copies);
-        let expected: HashMap<Register, u32> = vec![
-            (Register(1), 3),
-            (Register(2), 3),
-            (Register(3), 5),
-            (Register(4), 3),
-        ]
-        .into_iter()
-        .collect();
+        let result = execute_sequential(&ops);
+        let expected: HashMap<Location, u64> =
+            vec![(reg(1), 3), (reg(2), 3), (reg(3), 5), (reg(4), 3)]
+                .into_iter()
+                .collect();
         assert_eq!(result, expected);
     }
 
     #[test]
     fn test_execute_sequential_2() {
-        let copies = vec![
-            RegisterCopy {
-                source: Register(1),
-                destination: Register(4),
-            },
-            RegisterCopy {
-                source: Register(3),
-                destination: Register(1),
-            },
-            RegisterCopy {
-                source: Register(2),
-                destination: Register(3),
-            },
-            RegisterCopy {
-                source: Register(1),
-                destination: Register(2),
-            },
+        let ops = vec![
+            Op::Copy { source: reg(1), destination: reg(4) },
+            Op::Copy { source: reg(3), destination: reg(1) },
+            Op::Copy { source: reg(2), destination: reg(3) },
+            Op::Copy { source: reg(1), destination: reg(2) },
         ];
-        let result = execute_sequential(&copies);
-        assert_eq!(
-            result,
-            Vec::from_iter([
-                (Register(1), 3),
-                (Register(2), 3),
-                (Register(3), 2),
-                (Register(4), 1),
-            ])
-            .into_iter()
-            .collect::<HashMap<_, _>>()
-        );
+        let result = execute_sequential(&ops);
+        let expected: HashMap<Location, u64> =
+            vec![(reg(1), 3), (reg(2), 3), (reg(3), 2), (reg(4), 1)]
+                .into_iter()
+                .collect();
+        assert_eq!(result, expected);
     }
 
     #[test]
-    fn test_sequentialize_register_simple() {
+    fn test_sequentialize_simple() {
         let copies = vec![
-            RegisterCopy {
-                source: Register(1),
-                destination: Register(2),
-            },
-            RegisterCopy {
-                source: Register(2),
-                destination: Register(3),
-            },
-            RegisterCopy {
-                source: Register(3),
-                destination: Register(4),
-            },
+            Copy { source: reg(1), destination: reg(2) },
+            Copy { source: reg(2), destination: reg(3) },
+            Copy { source: reg(3), destination: reg(4) },
         ];
 
-        let spare = Register(5);
-        let result = sequentialize_register(&copies, spare);
+        let spare = reg(5);
+        let result = sequentialize(&copies, CycleBreak::Spare(spare));
         let sequential_result = execute_sequential(&result);
         assert_eq!(
             sequential_result,
-            Vec::from_iter([(Register(2), 1), (Register(3), 2), (Register(4), 3),])
+            Vec::from_iter([(reg(2), 1), (reg(3), 2), (reg(4), 3)])
                 .into_iter()
                 .collect::<HashMap<_, _>>()
         );
@@ -203,28 +262,35 @@ mod tests {
     #[test]
     fn test_sequentialize_cycle() {
         let copies = vec![
-            RegisterCopy {
-                source: Register(1),
-                destination: Register(2),
-            },
-            RegisterCopy {
-                source: Register(2),
-                destination: Register(3),
-            },
-            RegisterCopy {
-                source: Register(3),
-                destination: Register(1),
-            },
+            Copy { source: reg(1), destination: reg(2) },
+            Copy { source: reg(2), destination: reg(3) },
+            Copy { source: reg(3), destination: reg(1) },
         ];
-        let spare = Register(4);
-        let result = sequentialize_register(&copies, spare);
-        // print result for debugging
-        println!("{:?}", result);
+        let spare = reg(4);
+        let result = sequentialize(&copies, CycleBreak::Spare(spare));
         let mut sequential_result = execute_sequential(&result);
         assert_matches!(sequential_result.remove(&spare), Some(_));
         assert_eq!(
             sequential_result,
-            Vec::from_iter([(Register(2), 1), (Register(3), 2), (Register(1), 3),])
+            Vec::from_iter([(reg(2), 1), (reg(3), 2), (reg(1), 3)])
+                .into_iter()
+                .collect::<HashMap<_, _>>()
+        );
+    }
+
+    #[test]
+    fn test_sequentialize_cycle_with_swap() {
+        let copies = vec![
+            Copy { source: reg(1), destination: reg(2) },
+            Copy { source: reg(2), destination: reg(3) },
+            Copy { source: reg(3), destination: reg(1) },
+        ];
+        let result = sequentialize(&copies, CycleBreak::Swap);
+        assert!(result.iter().all(|op| !matches!(op, Op::Copy { .. })));
+        let sequential_result = execute_sequential(&result);
+        assert_eq!(
+            sequential_result,
+            Vec::from_iter([(reg(2), 1), (reg(3), 2), (reg(1), 3)])
                 .into_iter()
                 .collect::<HashMap<_, _>>()
         );
@@ -233,30 +299,51 @@ mod tests {
     #[test]
     fn test_sequentialize_with_fanin() {
         let copies = vec![
-            RegisterCopy {
-                source: Register(1),
-                destination: Register(2),
-            },
-            RegisterCopy {
-                source: Register(1),
-                destination: Register(3),
-            },
-            RegisterCopy {
-                source: Register(2),
-                destination: Register(1),
-            },
+            Copy { source: reg(1), destination: reg(2) },
+            Copy { source: reg(1), destination: reg(3) },
+            Copy { source: reg(2), destination: reg(1) },
         ];
-        let spare = Register(4);
-        let result = sequentialize_register(&copies, spare);
+        let spare = reg(4);
+        let result = sequentialize(&copies, CycleBreak::Spare(spare));
         let sequential_result = execute_sequential(&result);
         assert_eq!(
             sequential_result,
-            Vec::from_iter([(Register(2), 1), (Register(3), 1), (Register(1), 2)])
+            Vec::from_iter([(reg(2), 1), (reg(3), 1), (reg(1), 2)])
                 .into_iter()
                 .collect::<HashMap<_, _>>()
         );
     }
 
+    #[test]
+    fn test_sequentialize_stack_and_imm_leaves() {
+        // A register cycle plus two independent copies from leaf sources: a
+        // stack slot and an immediate, neither of which is ever a destination
+        // here.
+        let copies = vec![
+            Copy { source: reg(1), destination: reg(2) },
+            Copy { source: reg(2), destination: reg(1) },
+            Copy { source: Location::Stack(7), destination: reg(3) },
+            Copy { source: Location::Imm(42), destination: Location::Stack(9) },
+        ];
+        let spare = reg(4);
+        let result = sequentialize(&copies, CycleBreak::Spare(spare));
+        let mut sequential_result = execute_sequential(&result);
+        sequential_result.remove(&spare);
+        assert_eq!(sequential_result, execute_parallel(&copies));
+    }
+
+    #[test]
+    fn test_sequentialize_spare_class_mismatch_panics() {
+        let copies = vec![
+            Copy { source: reg(1), destination: reg(2) },
+            Copy { source: reg(2), destination: reg(1) },
+        ];
+        let result = std::panic::catch_unwind(|| {
+            sequentialize(&copies, CycleBreak::Spare(Location::Stack(0)))
+        });
+        assert!(result.is_err());
+    }
+
     #[test]
     fn test_sequentialize_rand() {
         let mut rng = rand::rng();
@@ -264,26 +351,47 @@ mod tests {
             let num_copies = 100;
             let mut copies = Vec::new();
             for i in 0..num_copies {
-                let dest = Register(i);
-                let src = Register(rng.random_range(0..num_copies));
+                let dest = reg(i);
+                let src = reg(rng.random_range(0..num_copies));
                 if src == dest {
                     continue; // Skip self-copies
                 }
-                copies.push(RegisterCopy {
-                    source: src,
-                    destination: dest,
-                });
+                copies.push(Copy { source: src, destination: dest });
             }
             // shuffle the copies.
             use rand::seq::SliceRandom;
 
             copies.shuffle(&mut rng);
-            let spare = Register(num_copies);
-            let result = sequentialize_register(&copies, spare);
+            let spare = reg(num_copies);
+            let result = sequentialize(&copies, CycleBreak::Spare(spare));
             let mut sequential_result = execute_sequential(&result);
             // remove the spare register from the result.
             sequential_result.remove(&spare);
             assert_eq!(sequential_result, execute_parallel(&copies));
         }
     }
+
+    #[test]
+    fn test_sequentialize_rand_swap() {
+        let mut rng = rand::rng();
+        for _ in 0..1000 {
+            let num_copies = 100;
+            let mut copies = Vec::new();
+            for i in 0..num_copies {
+                let dest = reg(i);
+                let src = reg(rng.random_range(0..num_copies));
+                if src == dest {
+                    continue; // Skip self-copies
+                }
+                copies.push(Copy { source: src, destination: dest });
+            }
+            // shuffle the copies.
+            use rand::seq::SliceRandom;
+
+            copies.shuffle(&mut rng);
+            let result = sequentialize(&copies, CycleBreak::Swap);
+            let sequential_result = execute_sequential(&result);
+            assert_eq!(sequential_result, execute_parallel(&copies));
+        }
+    }
 }
@@ -16,6 +16,295 @@ pub struct VisitOrder {
     pub pre: HashMap<Node, usize>,
     pub post: HashMap<Node, usize>,
     pub spanning_tree: HashMap<Node, Node>,
+    /// Nodes reachable from the root, in reverse-postorder: the order most
+    /// forward dataflow passes (and the dominator computation) want to walk
+    /// nodes in.
+    pub rpo: Vec<Node>,
+    pub rpo_index: HashMap<Node, usize>,
+}
+
+impl VisitOrder {
+    /// Whether `node` was reached from the root.
+    pub fn is_reachable(&self, node: Node) -> bool {
+        self.pre.contains_key(&node)
+    }
+}
+
+/// Immediate-dominator map for a `CfgGraph`, as computed by `dominators`.
+pub struct Dominators {
+    idom: HashMap<Node, Node>,
+}
+
+impl Dominators {
+    /// The immediate dominator of `node`, or `None` if `node` is unreachable
+    /// from the root (or is the root itself, which has no strict dominator).
+    pub fn idom(&self, node: Node) -> Option<Node> {
+        match self.idom.get(&node) {
+            Some(&idom) if idom != node => Some(idom),
+            _ => None,
+        }
+    }
+
+    /// Whether `a` dominates `b`, i.e. every path from the root to `b` passes
+    /// through `a`. A node always dominates itself.
+    pub fn dominates(&self, a: Node, b: Node) -> bool {
+        let mut current = b;
+        loop {
+            if current == a {
+                return true;
+            }
+            match self.idom.get(&current) {
+                Some(&next) if next != current => current = next,
+                _ => return false,
+            }
+        }
+    }
+}
+
+fn predecessors(graph: &CfgGraph) -> HashMap<Node, Vec<Node>> {
+    let mut predecessors: HashMap<Node, Vec<Node>> = HashMap::new();
+    for (&from, successors) in &graph.edges {
+        for &to in successors {
+            predecessors.entry(to).or_default().push(from);
+        }
+    }
+    predecessors
+}
+
+// Walks two "fingers" up the idom chain until they meet, using postorder
+// numbers to decide which finger to advance (the one with the smaller
+// postorder number is further from the root).
+fn intersect(idom: &HashMap<Node, Node>, post: &HashMap<Node, usize>, a: Node, b: Node) -> Node {
+    let mut finger1 = a;
+    let mut finger2 = b;
+    while finger1 != finger2 {
+        while post[&finger1] < post[&finger2] {
+            finger1 = idom[&finger1];
+        }
+        while post[&finger2] < post[&finger1] {
+            finger2 = idom[&finger2];
+        }
+    }
+    finger1
+}
+
+/// Computes the immediate-dominator tree of `graph` using the iterative
+/// Cooper-Harvey-Kennedy algorithm.
+pub fn dominators(graph: &CfgGraph) -> Dominators {
+    dominators_from_order(graph, &dfs_search(graph))
+}
+
+/// Same as `dominators`, but reuses an already-computed `VisitOrder` instead
+/// of running another DFS pass.
+fn dominators_from_order(graph: &CfgGraph, order: &VisitOrder) -> Dominators {
+    let preds = predecessors(graph);
+
+    let mut idom = HashMap::new();
+    idom.insert(graph.root, graph.root);
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &b in &order.rpo {
+            if b == graph.root {
+                continue;
+            }
+            let Some(preds_of_b) = preds.get(&b) else {
+                continue;
+            };
+            let mut defined_preds = preds_of_b.iter().copied().filter(|p| idom.contains_key(p));
+            let Some(first) = defined_preds.next() else {
+                continue;
+            };
+            let mut new_idom = first;
+            for p in defined_preds {
+                new_idom = intersect(&idom, &order.post, p, new_idom);
+            }
+
+            if idom.get(&b) != Some(&new_idom) {
+                idom.insert(b, new_idom);
+                changed = true;
+            }
+        }
+    }
+
+    Dominators { idom }
+}
+
+/// The role an edge plays in a depth-first spanning tree, per the standard
+/// tree/back/forward/cross classification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EdgeKind {
+    Tree,
+    Back,
+    Forward,
+    Cross,
+}
+
+pub struct EdgeClassification {
+    pub kinds: HashMap<(Node, Node), EdgeKind>,
+    /// Back edges, i.e. edges whose head is an ancestor of their tail in the
+    /// spanning tree. These are exactly the edges that close loops.
+    pub back_edges: Vec<(Node, Node)>,
+    /// True when every back edge's head dominates its tail, meaning the CFG
+    /// has no irreducible (multi-entry) loops.
+    pub reducible: bool,
+}
+
+/// Classifies every edge of `graph` against the spanning tree produced by
+/// `order`, and reports whether the graph is reducible. `dominators` must
+/// have been computed for the same `graph`.
+pub fn classify_edges(
+    graph: &CfgGraph,
+    order: &VisitOrder,
+    dominators: &Dominators,
+) -> EdgeClassification {
+    let mut kinds = HashMap::new();
+    let mut back_edges = Vec::new();
+
+    for (&from, successors) in &graph.edges {
+        for &to in successors {
+            let (Some(&pre_u), Some(&post_u)) = (order.pre.get(&from), order.post.get(&from))
+            else {
+                continue;
+            };
+            let (Some(&pre_v), Some(&post_v)) = (order.pre.get(&to), order.post.get(&to)) else {
+                continue;
+            };
+
+            let kind = if order.spanning_tree.get(&from) == Some(&to) {
+                EdgeKind::Tree
+            } else if pre_v <= pre_u && post_v >= post_u {
+                EdgeKind::Back
+            } else if pre_u < pre_v && post_u > post_v {
+                EdgeKind::Forward
+            } else {
+                EdgeKind::Cross
+            };
+
+            if kind == EdgeKind::Back {
+                back_edges.push((from, to));
+            }
+            kinds.insert((from, to), kind);
+        }
+    }
+
+    let reducible = back_edges
+        .iter()
+        .all(|&(tail, head)| dominators.dominates(head, tail));
+
+    EdgeClassification {
+        kinds,
+        back_edges,
+        reducible,
+    }
+}
+
+/// The natural loop of a back edge `n -> h`: the header `h` plus every node
+/// that can reach the tail `n` without passing through `h`. Loops that share
+/// a header are merged into one.
+pub struct Loop {
+    pub header: Node,
+    pub body: HashSet<Node>,
+    pub back_edges: Vec<(Node, Node)>,
+}
+
+/// A loop-nesting forest: a loop is nested inside another when its header
+/// lies in the other's body.
+pub struct LoopForest {
+    pub loops: Vec<Loop>,
+    parent: Vec<Option<usize>>,
+}
+
+impl LoopForest {
+    fn depth_of(&self, mut index: usize) -> usize {
+        let mut depth = 1;
+        while let Some(parent) = self.parent[index] {
+            depth += 1;
+            index = parent;
+        }
+        depth
+    }
+
+    /// The loop nesting depth of `node`: 0 if it is not in any loop,
+    /// otherwise the depth of the innermost loop containing it.
+    pub fn loop_depth(&self, node: Node) -> usize {
+        self.loops
+            .iter()
+            .enumerate()
+            .filter(|(_, l)| l.body.contains(&node))
+            .map(|(i, _)| self.depth_of(i))
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+/// Discovers the natural loops of `graph` and assembles them into a
+/// loop-nesting forest.
+///
+/// Panics if `graph` is irreducible (see `EdgeClassification::reducible`):
+/// natural loops with distinct headers can then contain each other, which
+/// has no well-defined nesting forest.
+pub fn natural_loops(graph: &CfgGraph) -> LoopForest {
+    let order = dfs_search(graph);
+    let dominators = dominators_from_order(graph, &order);
+    let classification = classify_edges(graph, &order, &dominators);
+    assert!(
+        classification.reducible,
+        "natural_loops requires a reducible graph"
+    );
+    let preds = predecessors(graph);
+
+    let mut by_header: HashMap<Node, Loop> = HashMap::new();
+    for (tail, head) in classification.back_edges {
+        let loop_ = by_header.entry(head).or_insert_with(|| Loop {
+            header: head,
+            body: HashSet::from([head]),
+            back_edges: Vec::new(),
+        });
+        loop_.back_edges.push((tail, head));
+
+        let mut worklist = vec![tail];
+        while let Some(node) = worklist.pop() {
+            if !loop_.body.insert(node) {
+                continue;
+            }
+            if let Some(node_preds) = preds.get(&node) {
+                for &pred in node_preds {
+                    if pred != head && !loop_.body.contains(&pred) {
+                        worklist.push(pred);
+                    }
+                }
+            }
+        }
+    }
+
+    let loops: Vec<Loop> = by_header.into_values().collect();
+    let parent = loops
+        .iter()
+        .enumerate()
+        .map(|(i, l)| {
+            loops
+                .iter()
+                .enumerate()
+                .filter(|(j, other)| *j != i && other.body.contains(&l.header))
+                .min_by_key(|(_, other)| other.body.len())
+                .map(|(j, _)| j)
+        })
+        .collect();
+
+    LoopForest { loops, parent }
+}
+
+// Explicit work-stack frames replacing the two phases of the recursive
+// traversal: `Enter` assigns a preorder number and starts scanning
+// successors; `Resume` picks the successor scan back up (one at a time, so
+// that visited-ness is re-checked after each child's whole subtree has been
+// explored, exactly as the recursive version's loop body does) and assigns
+// the postorder number once the scan runs out of successors.
+enum Frame {
+    Enter(Node),
+    Resume(Node, usize),
 }
 
 pub fn dfs_search(graph: &CfgGraph) -> VisitOrder {
@@ -25,61 +314,52 @@ pub fn dfs_search(graph: &CfgGraph) -> VisitOrder {
     let mut visited = HashSet::new();
     let mut pre_time = 0;
     let mut post_time = 0;
+    let mut postorder = Vec::new();
 
-    fn dfs(
-        node: &Node,
-        graph: &CfgGraph,
-        visited: &mut HashSet<Node>,
-        pre: &mut HashMap<Node, usize>,
-        post: &mut HashMap<Node, usize>,
-        spanning_tree: &mut HashMap<Node, Node>,
-        pre_time: &mut usize,
-        post_time: &mut usize,
-    ) {
-        if !visited.insert(*node) {
-            return;
-        }
-        pre.insert(*node, *pre_time);
-        *pre_time += 1;
-
-        if let Some(successors) = graph.edges.get(node) {
-            for successor in successors {
-                if visited.contains(successor) {
+    let mut stack = vec![Frame::Enter(graph.root)];
+    while let Some(frame) = stack.pop() {
+        match frame {
+            Frame::Enter(node) => {
+                if !visited.insert(node) {
                     continue;
                 }
-                spanning_tree.insert(*node, *successor);
-                dfs(
-                    successor,
-                    graph,
-                    visited,
-                    pre,
-                    post,
-                    spanning_tree,
-                    pre_time,
-                    post_time,
-                );
+                pre.insert(node, pre_time);
+                pre_time += 1;
+                stack.push(Frame::Resume(node, 0));
+            }
+            Frame::Resume(node, next_index) => {
+                let successors = graph.edges.get(&node);
+                let mut index = next_index;
+                let mut descended = false;
+                while let Some(successor) = successors.and_then(|s| s.get(index)) {
+                    index += 1;
+                    if visited.contains(successor) {
+                        continue;
+                    }
+                    spanning_tree.insert(node, *successor);
+                    stack.push(Frame::Resume(node, index));
+                    stack.push(Frame::Enter(*successor));
+                    descended = true;
+                    break;
+                }
+                if !descended {
+                    post.insert(node, post_time);
+                    post_time += 1;
+                    postorder.push(node);
+                }
             }
         }
-
-        post.insert(*node, *post_time);
-        *post_time += 1;
     }
 
-    dfs(
-        &graph.root,
-        graph,
-        &mut visited,
-        &mut pre,
-        &mut post,
-        &mut spanning_tree,
-        &mut pre_time,
-        &mut post_time,
-    );
+    let rpo: Vec<Node> = postorder.into_iter().rev().collect();
+    let rpo_index = rpo.iter().enumerate().map(|(i, &n)| (n, i)).collect();
 
     VisitOrder {
         pre,
         post,
         spanning_tree,
+        rpo,
+        rpo_index,
     }
 }
 
@@ -105,5 +385,175 @@ mod tests {
             order.spanning_tree,
             HashMap::from([(a, b), (b, c), (c, d),])
         );
+        assert_eq!(order.rpo, vec![a, b, c, d]);
+        assert_eq!(
+            order.rpo_index,
+            HashMap::from([(a, 0), (b, 1), (c, 2), (d, 3)])
+        );
+    }
+
+    #[test]
+    fn test_dfs_search_unreachable_node() {
+        let a = Node { id: 0 };
+        let b = Node { id: 1 };
+        let unreachable = Node { id: 2 };
+        let graph = CfgGraph {
+            root: a,
+            nodes: vec![a, b, unreachable],
+            edges: HashMap::from([(a, vec![b])]),
+        };
+        let order = dfs_search(&graph);
+        assert!(order.is_reachable(a));
+        assert!(order.is_reachable(b));
+        assert!(!order.is_reachable(unreachable));
+        assert!(!order.rpo.contains(&unreachable));
+    }
+
+    #[test]
+    fn test_dominators_diamond() {
+        // a -> b -> d
+        // a -> c -> d
+        let a = Node { id: 0 };
+        let b = Node { id: 1 };
+        let c = Node { id: 2 };
+        let d = Node { id: 3 };
+        let graph = CfgGraph {
+            root: a,
+            nodes: vec![a, b, c, d],
+            edges: HashMap::from([(a, vec![b, c]), (b, vec![d]), (c, vec![d])]),
+        };
+        let dominators = dominators(&graph);
+        assert_eq!(dominators.idom(a), None);
+        assert_eq!(dominators.idom(b), Some(a));
+        assert_eq!(dominators.idom(c), Some(a));
+        assert_eq!(dominators.idom(d), Some(a));
+        assert!(dominators.dominates(a, d));
+        assert!(!dominators.dominates(b, d));
+        assert!(!dominators.dominates(c, d));
+    }
+
+    #[test]
+    fn test_dominators_loop() {
+        // a -> b -> c -> b (back edge), c -> d
+        let a = Node { id: 0 };
+        let b = Node { id: 1 };
+        let c = Node { id: 2 };
+        let d = Node { id: 3 };
+        let graph = CfgGraph {
+            root: a,
+            nodes: vec![a, b, c, d],
+            edges: HashMap::from([(a, vec![b]), (b, vec![c]), (c, vec![b, d])]),
+        };
+        let dominators = dominators(&graph);
+        assert_eq!(dominators.idom(b), Some(a));
+        assert_eq!(dominators.idom(c), Some(b));
+        assert_eq!(dominators.idom(d), Some(c));
+        assert!(dominators.dominates(b, d));
+    }
+
+    #[test]
+    fn test_classify_edges_reducible_loop() {
+        let a = Node { id: 0 };
+        let b = Node { id: 1 };
+        let c = Node { id: 2 };
+        let d = Node { id: 3 };
+        let graph = CfgGraph {
+            root: a,
+            nodes: vec![a, b, c, d],
+            edges: HashMap::from([(a, vec![b]), (b, vec![c]), (c, vec![b, d])]),
+        };
+        let order = dfs_search(&graph);
+        let dominators = dominators_from_order(&graph, &order);
+        let classification = classify_edges(&graph, &order, &dominators);
+        assert_eq!(classification.back_edges, vec![(c, b)]);
+        assert_eq!(classification.kinds[&(a, b)], EdgeKind::Tree);
+        assert_eq!(classification.kinds[&(b, c)], EdgeKind::Tree);
+        assert_eq!(classification.kinds[&(c, d)], EdgeKind::Tree);
+        assert_eq!(classification.kinds[&(c, b)], EdgeKind::Back);
+        assert!(classification.reducible);
+    }
+
+    #[test]
+    fn test_classify_edges_irreducible() {
+        // Two distinct headers (b and c) shared by a single loop body, with
+        // entries from outside jumping into both: no single header dominates
+        // the loop, so the back edge c -> b has a head that does not
+        // dominate its tail.
+        let a = Node { id: 0 };
+        let b = Node { id: 1 };
+        let c = Node { id: 2 };
+        let graph = CfgGraph {
+            root: a,
+            nodes: vec![a, b, c],
+            edges: HashMap::from([(a, vec![b, c]), (b, vec![c]), (c, vec![b])]),
+        };
+        let order = dfs_search(&graph);
+        let dominators = dominators_from_order(&graph, &order);
+        let classification = classify_edges(&graph, &order, &dominators);
+        assert!(!classification.reducible);
+    }
+
+    #[test]
+    fn test_natural_loops_nested() {
+        // a -> b -> c -> d -> c (inner loop, header c) -> b (outer loop, header b) -> e
+        let a = Node { id: 0 };
+        let b = Node { id: 1 };
+        let c = Node { id: 2 };
+        let d = Node { id: 3 };
+        let e = Node { id: 4 };
+        let graph = CfgGraph {
+            root: a,
+            nodes: vec![a, b, c, d, e],
+            edges: HashMap::from([(a, vec![b]), (b, vec![c, e]), (c, vec![d]), (d, vec![c, b])]),
+        };
+
+        let forest = natural_loops(&graph);
+        assert_eq!(forest.loops.len(), 2);
+
+        let inner = forest
+            .loops
+            .iter()
+            .find(|l| l.header == c)
+            .expect("inner loop with header c");
+        assert_eq!(inner.body, HashSet::from([c, d]));
+
+        let outer = forest
+            .loops
+            .iter()
+            .find(|l| l.header == b)
+            .expect("outer loop with header b");
+        assert_eq!(outer.body, HashSet::from([b, c, d]));
+
+        assert_eq!(forest.loop_depth(a), 0);
+        assert_eq!(forest.loop_depth(b), 1);
+        assert_eq!(forest.loop_depth(c), 2);
+        assert_eq!(forest.loop_depth(d), 2);
+        assert_eq!(forest.loop_depth(e), 0);
+    }
+
+    #[test]
+    fn test_natural_loops_irreducible_panics() {
+        // Two loops (headers n1 and n0) whose bodies each contain the
+        // other's header: there is no entry node that dominates the other,
+        // so neither loop can be said to nest inside the other.
+        let n0 = Node { id: 0 };
+        let n1 = Node { id: 1 };
+        let n2 = Node { id: 2 };
+        let n3 = Node { id: 3 };
+        let n4 = Node { id: 4 };
+        let graph = CfgGraph {
+            root: n0,
+            nodes: vec![n0, n1, n2, n3, n4],
+            edges: HashMap::from([
+                (n0, vec![n1, n2, n4]),
+                (n1, vec![n0, n4]),
+                (n2, vec![n0, n1, n3, n4]),
+                (n3, vec![n0, n1]),
+                (n4, vec![n0, n3]),
+            ]),
+        };
+
+        let result = std::panic::catch_unwind(|| natural_loops(&graph));
+        assert!(result.is_err());
     }
 }